@@ -1,25 +1,87 @@
 use anyhow::{bail, Context};
-use bpaf::Bpaf;
+use bpaf::{Bpaf, Parser};
+use command_group::{CommandGroup, GroupChild};
 use enum_map::{enum_map, Enum};
-use inotify::{EventMask, Inotify, WatchMask};
+use nix::fcntl::{flock, FlockArg};
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::collections::HashMap;
 use std::fmt;
 use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::process::{self, Command};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tracing::*;
 
-#[derive(Bpaf)]
+/// How big a job's log is allowed to get before we rotate it, by default.
+const DEFAULT_LOG_ROTATE_BYTES: u64 = 10 * 1024 * 1024;
+/// How many rotated generations of a job's log we keep around, by default.
+const DEFAULT_LOG_ROTATE_KEEP: usize = 5;
+/// How long to wait after SIGTERM before escalating a cancellation to SIGKILL.
+const CANCEL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+#[derive(Bpaf, Clone, Debug)]
 #[bpaf(options)]
-struct Opts {
-    #[bpaf(short, long, fallback(1))]
-    jobs: usize,
-    #[bpaf(positional("COMMAND"))]
-    cmd: Option<String>,
+enum Opts {
+    /// Show the status of all jobs in the queue (default)
+    #[bpaf(command)]
+    Status,
+    /// Print a job's captured output
+    #[bpaf(command)]
+    Log {
+        #[bpaf(positional("ID"))]
+        id: usize,
+    },
+    /// List finished jobs together with their metadata
+    #[bpaf(command)]
+    Archive,
+    /// Cancel a waiting or running job
+    #[bpaf(command)]
+    Cancel {
+        #[bpaf(positional("ID"))]
+        id: usize,
+    },
+    /// Run a command once its `--after` dependencies have finished and a job slot is free
+    Run {
+        /// Maximum number of jobs running at once, across the whole queue
+        #[bpaf(short, long, fallback(1))]
+        jobs: usize,
+        /// Wait for the job or tag with this id/name to finish before starting
+        #[bpaf(long("after"))]
+        after: Vec<String>,
+        /// Tag this job so that later jobs can `--after` it by name
+        #[bpaf(long("tag"))]
+        tags: Vec<String>,
+        /// Retry the command up to this many times if it exits non-zero
+        #[bpaf(long, fallback(0))]
+        retries: u32,
+        /// How long to wait before the first retry (doubled after each one)
+        #[bpaf(external)]
+        retry_delay: Duration,
+        #[bpaf(positional("COMMAND"))]
+        cmd: String,
+    },
+}
+
+fn retry_delay() -> impl bpaf::Parser<Duration> {
+    bpaf::long("retry-delay")
+        .argument::<String>("DURATION")
+        .parse(|s| humantime::parse_duration(&s))
+        .fallback(Duration::from_secs(1))
 }
 
 fn main() {
-    if let Err(e) = main_2(opts().run()) {
+    if let Err(e) = main_2(opts().fallback(Opts::Status).run()) {
         let es = e.chain().map(|x| x.to_string()).collect::<Vec<_>>();
         error!("{}", es.join(": "));
         process::exit(1);
@@ -40,12 +102,22 @@ fn main_2(opts: Opts) -> anyhow::Result<()> {
     let qdir = std::env::var("QUEUE_DIR").map_or(PathBuf::from(".patiently"), PathBuf::from);
     std::fs::create_dir_all(&qdir)?;
 
-    match opts.cmd {
-        None => status(&qdir)?,
-        Some(cmd) => {
-            let mut state = State::new(qdir)?;
+    match opts {
+        Opts::Status => status(&qdir)?,
+        Opts::Log { id } => log_cmd(&qdir, id)?,
+        Opts::Archive => archive(&qdir)?,
+        Opts::Cancel { id } => cancel(&qdir, id)?,
+        Opts::Run {
+            jobs,
+            after,
+            tags,
+            retries,
+            retry_delay,
+            cmd,
+        } => {
+            let mut state = State::new(qdir, &cmd, after, tags)?;
             let res = info_span!("", id = state.id)
-                .in_scope(|| run_job(&mut state, cmd, opts.jobs))
+                .in_scope(|| run_job(&mut state, cmd, jobs, retries, retry_delay))
                 .context(state.id);
             if let Err(e) = res {
                 // Make an attempt to mark the job as crashed, ignoring new errors
@@ -77,60 +149,299 @@ fn status(qdir: &Path) -> anyhow::Result<()> {
         for (status, count) in totals {
             writeln!(tp.buf, "{:>10}: {count}", status.to_string())?;
         }
+        for &(id, status) in &jobs {
+            if status != Status::Running {
+                continue;
+            }
+            let retry_suffix = match read_meta(&qdir, id) {
+                Ok(meta) if meta.attempt > 0 => {
+                    format!(" (retry {}/{})", meta.attempt, meta.retries)
+                }
+                _ => String::new(),
+            };
+            match read_progress(&qdir, id) {
+                Some((frac, msg)) => {
+                    let pct = (frac * 100.0).round();
+                    writeln!(
+                        tp.buf,
+                        "  #{id} {} {pct:>3}% {msg}{retry_suffix}",
+                        progress_bar(frac)
+                    )?;
+                }
+                None => writeln!(tp.buf, "  #{id} running{retry_suffix}")?,
+            }
+        }
         tp.print()?;
 
         if n_unfinished == 0 {
             break;
         }
 
-        std::thread::sleep(std::time::Duration::from_secs(1));
+        std::thread::sleep(Duration::from_secs(1));
+    }
+    Ok(())
+}
+
+/// Print a job's captured output, following it until the job finishes.
+fn log_cmd(qdir: &Path, id: usize) -> anyhow::Result<()> {
+    let path = log_path(qdir, id, 0);
+    let mut file = File::open(&path).with_context(|| format!("{id}: No log for this job"))?;
+    let mut buf = Vec::new();
+    // Bytes we've already shown, tracked ourselves rather than relying on the
+    // handle's own cursor: rotation truncates this same path in place, which
+    // doesn't move the cursor, so a bare `read_to_end` would see cursor > len
+    // and go quiet forever, then resume at a stale offset once the child's
+    // append-mode writes grow the file past that point again.
+    let mut consumed = 0u64;
+    let stdout = std::io::stdout();
+    loop {
+        let len = file.metadata()?.len();
+        if len < consumed {
+            // The file is shorter than what we've already shown; a rotation
+            // must have truncated it out from under us. Start over from the
+            // beginning of its new contents.
+            consumed = 0;
+        }
+        file.seek(SeekFrom::Start(consumed))?;
+        buf.clear();
+        file.read_to_end(&mut buf)?;
+        consumed += buf.len() as u64;
+        if !buf.is_empty() {
+            stdout.lock().write_all(&buf)?;
+        }
+        let still_running = list_jobs(qdir)?
+            .into_iter()
+            .find(|(x, _)| *x == id)
+            .map_or(false, |(_, status)| !status.is_finished());
+        if !still_running {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+    Ok(())
+}
+
+/// List finished jobs together with the metadata recorded for each.
+fn archive(qdir: &Path) -> anyhow::Result<()> {
+    for (id, status) in list_jobs(qdir)? {
+        if !status.is_finished() {
+            continue;
+        }
+        match read_meta(qdir, id) {
+            Ok(meta) => {
+                let exit = meta
+                    .exit_code
+                    .map_or_else(|| "-".to_string(), |c| c.to_string());
+                println!("{id:>6}  {status:<10}  exit={exit:<4}  {}", meta.cmd);
+            }
+            Err(_) => println!("{id:>6}  {status:<10}  (no metadata)"),
+        }
     }
     Ok(())
 }
 
-fn run_job(state: &mut State, cmd: String, jobs: usize) -> anyhow::Result<()> {
+/// Cancel a job: a waiting job is simply marked cancelled, a running one is
+/// sent SIGTERM (and, if it's still around after a grace period, SIGKILL).
+fn cancel(qdir: &Path, id: usize) -> anyhow::Result<()> {
+    let (_, status) = list_jobs(qdir)?
+        .into_iter()
+        .find(|(x, _)| *x == id)
+        .with_context(|| format!("{id}: No such job"))?;
+    match status {
+        Status::Waiting => {
+            std::fs::rename(
+                qdir.join(format!("patiently.{id}.{}", Status::Waiting)),
+                qdir.join(format!("patiently.{id}.{}", Status::Cancelled)),
+            )
+            .context("Cancelling queued job")?;
+        }
+        Status::Running => {
+            let pgid = read_meta(qdir, id)?
+                .pgid
+                .with_context(|| format!("{id}: No process group recorded for this job"))?;
+            info!(pgid, "Sending SIGTERM");
+            signal_group(pgid, Signal::SIGTERM)?;
+            std::thread::sleep(CANCEL_GRACE_PERIOD);
+            let still_running = list_jobs(qdir)?
+                .into_iter()
+                .any(|(x, s)| x == id && s == Status::Running);
+            if still_running {
+                warn!(pgid, "Still running after SIGTERM, sending SIGKILL");
+                signal_group(pgid, Signal::SIGKILL)?;
+            }
+        }
+        _ => bail!("{id}: Job has already finished ({status})"),
+    }
+    Ok(())
+}
+
+fn signal_group(pgid: i32, sig: Signal) -> anyhow::Result<()> {
+    signal::killpg(Pid::from_raw(pgid), sig).context("Sending signal to process group")
+}
+
+fn run_job(
+    state: &mut State,
+    cmd: String,
+    jobs: usize,
+    retries: u32,
+    retry_delay: Duration,
+) -> anyhow::Result<()> {
     state
         .wait_for_precursors(jobs)
         .context("While waiting for precursors")?;
 
-    state.change_status(Status::Running)?;
-    let exit_code = Command::new("bash").arg("-c").arg(cmd).status()?;
+    state.update_meta(|m| {
+        m.started = Some(now_secs());
+        m.retries = retries;
+    })?;
+
+    let progress_path = progress_path(&state.qdir, state.id);
+    File::create(&progress_path).context("Creating progress file")?;
+
+    let mut attempt = 0;
+    let exit_code = loop {
+        let exit_code = run_attempt(state, &cmd, &progress_path)?;
+        if exit_code.success() || attempt >= retries {
+            break exit_code;
+        }
+        // Exponential backoff, capped well short of what `<<` can overflow.
+        // Shift by the pre-increment attempt count so the first retry waits
+        // exactly `retry_delay`, doubling on each one after that.
+        let backoff = retry_delay.saturating_mul(1 << attempt.min(16));
+        attempt += 1;
+        warn!(
+            attempt,
+            retries,
+            ?backoff,
+            "Job failed, retrying after backoff"
+        );
+        state.update_meta(|m| m.attempt = attempt)?;
+        std::thread::sleep(backoff);
+    };
 
     let final_status = if exit_code.success() {
         Status::Finished
     } else {
         Status::Failed
     };
+    state.update_meta(|m| {
+        m.ended = Some(now_secs());
+        m.exit_code = exit_code.code();
+        m.pgid = None;
+    })?;
     state.change_status(final_status)?;
     Ok(())
 }
 
+/// Run the job's command once to completion, returning its exit status.
+/// The job's status file is left untouched: staying `Running` across
+/// retries means waiters on it don't wake up prematurely.
+fn run_attempt(
+    state: &mut State,
+    cmd: &str,
+    progress_path: &Path,
+) -> anyhow::Result<process::ExitStatus> {
+    let stdout = File::options()
+        .append(true)
+        .open(state.log_path())
+        .context("Opening log file")?;
+    let stderr = stdout.try_clone().context("Duplicating log file handle")?;
+
+    // Run the job in its own process group so that we can signal the whole
+    // tree of descendants at once, rather than just the immediate child.
+    let mut child: GroupChild = Command::new("bash")
+        .arg("-c")
+        .arg(cmd)
+        .env("PATIENTLY_PROGRESS", progress_path)
+        .stdout(stdout)
+        .stderr(stderr)
+        .group_spawn()
+        .context("Spawning job")?;
+    let pgid = child.id() as i32;
+    state.update_meta(|m| m.pgid = Some(pgid))?;
+
+    let stop_rotating = Arc::new(AtomicBool::new(false));
+    let rotator = {
+        let stop_rotating = stop_rotating.clone();
+        let qdir = state.qdir.clone();
+        let id = state.id;
+        std::thread::spawn(move || {
+            while !stop_rotating.load(Ordering::Relaxed) {
+                if let Err(e) = rotate_log(&qdir, id) {
+                    warn!("{e:#}");
+                }
+                std::thread::sleep(Duration::from_secs(5));
+            }
+        })
+    };
+
+    // Forward SIGINT/SIGTERM to the job's process group, so that Ctrl-C on
+    // `patiently` itself takes the whole job tree down with it.
+    let mut signals = Signals::new([SIGINT, SIGTERM]).context("Installing signal handler")?;
+    let signals_handle = signals.handle();
+    let forwarder = std::thread::spawn(move || {
+        for sig in signals.forever() {
+            let sig = Signal::try_from(sig).expect("Unexpected signal number");
+            warn!(%sig, "Forwarding signal to job's process group");
+            let _ = signal::killpg(Pid::from_raw(pgid), sig);
+        }
+    });
+
+    let exit_code = child.wait().context("Waiting for job")?;
+
+    signals_handle.close();
+    let _ = forwarder.join();
+    stop_rotating.store(true, Ordering::Relaxed);
+    let _ = rotator.join();
+
+    Ok(exit_code)
+}
+
 struct State {
     qdir: PathBuf,
     id: usize,
     status: Status,
-    // Reflects the status at the time new() was called.  May be stale.
-    precursors: Vec<(usize, Status)>,
+    // The ids this job must wait for, resolved from `--after` at claim time.
+    deps: Vec<usize>,
 }
 
 impl State {
-    fn new(qdir: PathBuf) -> anyhow::Result<State> {
-        let (id, precursors) = loop {
-            let (id, precursors) = get_precusors(&qdir).context("Get precursors")?;
+    fn new(
+        qdir: PathBuf,
+        cmd: &str,
+        after: Vec<String>,
+        tags: Vec<String>,
+    ) -> anyhow::Result<State> {
+        let deps = resolve_deps(&qdir, &after).context("Resolving --after")?;
+        check_acyclic(&qdir, &deps).context("Checking for dependency cycles")?;
+
+        let id = loop {
+            let id = next_id(&qdir)?;
             // Try to create the queue file
             let path = qdir.join(format!("patiently.{id}.{}", Status::Waiting));
             let res = File::options().create_new(true).append(true).open(path);
             if res.is_ok() {
                 // We claimed this name
-                break (id, precursors);
+                break id;
             }
             // Someone else got there first.  Retry
         };
-        // TODO: Check precursor flocks, set to "cancelled" if missing
+        File::create(log_path(&qdir, id, 0)).context("Creating log file")?;
+        write_meta(
+            &qdir,
+            id,
+            &Meta {
+                cmd: cmd.to_string(),
+                submitted: now_secs(),
+                ..Meta::default()
+            },
+        )
+        .context("Creating meta file")?;
+        write_deps(&qdir, id, &Deps { after, tags }).context("Creating deps file")?;
         Ok(State {
             qdir,
             id,
-            precursors,
+            deps,
             status: Status::Waiting,
         })
     }
@@ -147,110 +458,149 @@ impl State {
         Ok(())
     }
 
-    fn qfile(&self) -> PathBuf {
-        self.qdir
-            .join(format!("patiently.{}.{}", self.id, self.status))
+    fn log_path(&self) -> PathBuf {
+        log_path(&self.qdir, self.id, 0)
     }
 
+    fn update_meta(&self, f: impl FnOnce(&mut Meta)) -> anyhow::Result<()> {
+        let mut meta = read_meta(&self.qdir, self.id)?;
+        f(&mut meta);
+        write_meta(&self.qdir, self.id, &meta)
+    }
+
+    /// Block until the declared dependencies are finished, then atomically
+    /// claim one of the `max_jobs` concurrency slots (flipping ourselves to
+    /// `Running` in the process).
     fn wait_for_precursors(&mut self, max_jobs: usize) -> anyhow::Result<()> {
-        info!("Waiting for {} jobs to finish", self.precursors.len());
-        let mut inotify = loop {
-            match Inotify::init() {
-                Ok(x) => break x,
-                Err(_) => std::thread::sleep(std::time::Duration::from_secs(1)),
-            }
-        };
+        self.wait_for_deps()?;
+        self.claim_concurrency_slot(max_jobs)
+    }
 
-        let mut inotify_buf = vec![0; 1024];
-        let our_wd =
-            match inotify.add_watch(&self.qfile(), WatchMask::DELETE_SELF | WatchMask::MOVE_SELF) {
-                Ok(x) => x,
-                Err(_) => {
-                    // Our output file has already been deleted
+    fn wait_for_deps(&mut self) -> anyhow::Result<()> {
+        info!("Waiting for {} dependencies to finish", self.deps.len());
+        // Register the watch *before* deciding what's still pending, and derive
+        // `pending` from the watcher's own baseline. If we scanned first and
+        // registered the watch second, a dependency could finish in between,
+        // and the watcher's baseline would then bake in "finished" with no
+        // transition ever recorded for it, leaving us waiting forever.
+        let mut watcher = QueueWatcher::new(&self.qdir)?;
+        let mut pending: std::collections::HashSet<usize> = self
+            .deps
+            .iter()
+            .copied()
+            .filter(|id| !matches!(watcher.known.get(id), Some(status) if status.is_finished()))
+            .collect();
+        while !pending.is_empty() {
+            match watcher.next_event()? {
+                QueueEvent::Removed { id } if id == self.id => {
+                    // Our own queue file is gone; someone's cleaned up behind us.
                     warn!("Output file removed, exiting");
                     process::exit(0);
                 }
-            };
-        let mut watches = std::collections::HashMap::new();
+                QueueEvent::StatusChanged { id, new }
+                    if pending.contains(&id) && new.is_finished() =>
+                {
+                    pending.remove(&id);
+                }
+                QueueEvent::Removed { id } if pending.contains(&id) => {
+                    // The dependency's queue file vanished entirely; treat it as finished.
+                    pending.remove(&id);
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
 
+    /// Wait for fewer than `max_jobs` jobs to be `Running`, then flip
+    /// ourselves to `Running` before anyone else gets a chance to observe
+    /// the freed-up slot.
+    ///
+    /// This is a global cap shared by every `patiently` process pointed at
+    /// the same queue directory, so a plain check-then-act poll isn't
+    /// enough: whenever several jobs become eligible around the same
+    /// moment (e.g. a burst of dependents unblocked by the same finished
+    /// precursor), they'd all observe the same stale count in their own
+    /// loop and all proceed, defeating the cap in exactly the scenario it
+    /// exists for. Instead we serialize the count-and-claim across
+    /// processes with an flock on a cap file in the queue directory.
+    fn claim_concurrency_slot(&mut self, max_jobs: usize) -> anyhow::Result<()> {
+        let lock_path = self.qdir.join("patiently.lock");
         loop {
-            while watches.len() < max_jobs {
-                match self.precursors.pop() {
-                    Some((x, _)) => {
-                        match inotify.add_watch(
-                            &self.qdir.join(format!("patiently.{x}.{}", Status::Waiting)),
-                            WatchMask::DELETE_SELF | WatchMask::MOVE_SELF,
-                        ) {
-                            Ok(wd) => {
-                                watches.insert(wd, x);
-                            }
-                            Err(_) => {
-                                if let Ok(wd) = inotify.add_watch(
-                                    &self.qdir.join(format!("patiently.{x}.{}", Status::Running)),
-                                    WatchMask::DELETE_SELF | WatchMask::MOVE_SELF,
-                                ) {
-                                    watches.insert(wd, x);
-                                } else {
-                                    // I guess it finished already?
-                                }
-                            }
-                        }
-                    }
-                    None => return Ok(()),
-                }
+            let lock_file = File::options()
+                .create(true)
+                .write(true)
+                .open(&lock_path)
+                .context("Opening concurrency cap lock")?;
+            flock(lock_file.as_raw_fd(), FlockArg::LockExclusive)
+                .context("Locking concurrency cap")?;
+            let running = list_jobs(&self.qdir)?
+                .into_iter()
+                .filter(|(_, status)| *status == Status::Running)
+                .count();
+            if running < max_jobs {
+                self.change_status(Status::Running)?;
+                return Ok(());
             }
-            for ev in inotify
-                .read_events_blocking(&mut inotify_buf)
-                .context("Getting events")?
-            {
-                if ev.wd == our_wd {
-                    warn!("Output file removed, exiting");
-                    process::exit(0);
-                }
-                match ev.mask {
-                    EventMask::IGNORED | EventMask::DELETE_SELF => {
-                        watches.remove(&ev.wd);
-                        continue;
-                    }
-                    EventMask::MOVE_SELF => {
-                        let x = match watches.get(&ev.wd) {
-                            Some(x) => x,
-                            None => bail!("{:?}: Couldn't find watch", ev),
-                        };
-                        let exists =
-                            |status| self.qdir.join(format!("patiently.{x}.{status}")).exists();
-                        if exists(Status::Running) {
-                            // The file just switched to "running" status.
-                            // Keep watching it.
-                        } else if exists(Status::Finished)
-                            || exists(Status::Failed)
-                            || exists(Status::Crashed)
-                        {
-                            // The file just switched to "finished"/"failed"
-                            // status.  Remove the watch.
-                            inotify.rm_watch(ev.wd).context("Removing watch")?;
-                        } else if exists(Status::Waiting) {
-                            bail!("File moved, but status is still waiting?");
-                        } else {
-                            // Someone has renamed the file to something we
-                            // don't recognise.
-                            inotify.rm_watch(ev.wd).context("Removing watch")?;
-                        }
-                    }
-                    mask => bail!("Unexpected event mask {:?}", mask),
+            drop(lock_file);
+            std::thread::sleep(Duration::from_millis(200));
+        }
+    }
+}
+
+fn next_id(qdir: &Path) -> anyhow::Result<usize> {
+    Ok(list_jobs(qdir)?
+        .iter()
+        .map(|x| x.0)
+        .max()
+        .map_or(0, |x| x + 1))
+}
+
+/// Resolve a list of `--after` arguments (each either a bare job id or a
+/// tag name) to the concrete set of job ids they refer to.
+fn resolve_deps(qdir: &Path, after: &[String]) -> anyhow::Result<Vec<usize>> {
+    let mut ids = std::collections::BTreeSet::new();
+    for a in after {
+        if let Ok(id) = a.parse::<usize>() {
+            ids.insert(id);
+        } else {
+            for (id, _) in list_jobs(qdir)? {
+                if read_deps(qdir, id).map_or(false, |d| d.tags.contains(a)) {
+                    ids.insert(id);
                 }
             }
         }
     }
+    Ok(ids.into_iter().collect())
 }
 
-fn get_precusors(qdir: &Path) -> anyhow::Result<(usize, Vec<(usize, Status)>)> {
-    let mut precursors = list_jobs(qdir)?;
-    // Increment the ID regardless of the status
-    let next_id = precursors.iter().map(|x| x.0).max().map_or(0, |x| x + 1);
-    // Don't wait for completed jobs
-    precursors.retain(|(_, status)| !status.is_finished());
-    Ok((next_id, precursors))
+/// Reject a dependency set that leads into a cycle, by walking the
+/// declared-dependency sidecars of every job transitively reachable from it.
+/// The job being submitted has no id yet and is not part of the walk, so a
+/// cycle that it would close (an earlier job's `--after` naming the id this
+/// job goes on to claim) is not detected.
+fn check_acyclic(qdir: &Path, deps: &[usize]) -> anyhow::Result<()> {
+    fn visit(
+        qdir: &Path,
+        id: usize,
+        visiting: &mut std::collections::HashSet<usize>,
+    ) -> anyhow::Result<()> {
+        if !visiting.insert(id) {
+            bail!("{id}: Dependency cycle detected");
+        }
+        if let Ok(deps) = read_deps(qdir, id) {
+            for dep in resolve_deps(qdir, &deps.after)? {
+                visit(qdir, dep, visiting)?;
+            }
+        }
+        visiting.remove(&id);
+        Ok(())
+    }
+    let mut visiting = std::collections::HashSet::new();
+    for &id in deps {
+        visit(qdir, id, &mut visiting)?;
+    }
+    Ok(())
 }
 
 fn list_jobs(qdir: &Path) -> anyhow::Result<Vec<(usize, Status)>> {
@@ -261,27 +611,252 @@ fn list_jobs(qdir: &Path) -> anyhow::Result<Vec<(usize, Status)>> {
                 Ok(ft) if ft.is_file() => (),
                 _ => return None,
             }
-            let name = x.file_name();
-            let mut tokens = name.to_str()?.split('.');
-            if tokens.next()? != "patiently" {
-                return None;
-            }
-            let id: usize = tokens.next()?.parse().ok()?;
-            let status: Status = tokens.next()?.parse().ok()?;
-            Some((id, status))
+            parse_job_file(x.file_name().to_str()?)
         })
         .collect::<Vec<_>>();
     jobs.sort_unstable_by_key(|x| x.0);
     Ok(jobs)
 }
 
-#[derive(Copy, Clone, Enum)]
+/// Parse a `patiently.<id>.<status>` file name into its id and status.
+fn parse_job_file(name: &str) -> Option<(usize, Status)> {
+    let mut tokens = name.split('.');
+    if tokens.next()? != "patiently" {
+        return None;
+    }
+    let id: usize = tokens.next()?.parse().ok()?;
+    let status: Status = tokens.next()?.parse().ok()?;
+    Some((id, status))
+}
+
+/// A semantic queue event, derived from raw filesystem notifications by
+/// diffing directory contents rather than interpreting any one backend's
+/// rename/delete event shape directly.
+enum QueueEvent {
+    StatusChanged { id: usize, new: Status },
+    Removed { id: usize },
+}
+
+/// Watches a queue directory with `notify` (portable across Linux, macOS and
+/// Windows) and turns its filesystem events into [`QueueEvent`]s.
+struct QueueWatcher {
+    qdir: PathBuf,
+    // Kept alive for as long as the watcher should keep running.
+    _watcher: RecommendedWatcher,
+    rx: Receiver<notify::Result<notify::Event>>,
+    known: HashMap<usize, Status>,
+}
+
+impl QueueWatcher {
+    fn new(qdir: &Path) -> anyhow::Result<QueueWatcher> {
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(move |res| {
+            let _ = tx.send(res);
+        })
+        .context("Starting filesystem watcher")?;
+        watcher
+            .watch(qdir, RecursiveMode::NonRecursive)
+            .context("Watching queue directory")?;
+        Ok(QueueWatcher {
+            qdir: qdir.to_path_buf(),
+            _watcher: watcher,
+            rx,
+            known: list_jobs(qdir)?.into_iter().collect(),
+        })
+    }
+
+    /// Block until a directory change produces a semantic queue event.
+    fn next_event(&mut self) -> anyhow::Result<QueueEvent> {
+        loop {
+            let event = self
+                .rx
+                .recv()
+                .context("Filesystem watcher disconnected")?
+                .context("Watching queue directory")?;
+            let ids: std::collections::HashSet<usize> = event
+                .paths
+                .iter()
+                .filter_map(|p| p.file_name()?.to_str())
+                .filter_map(parse_job_file)
+                .map(|(id, _)| id)
+                .collect();
+            // Resolve the authoritative status by re-scanning, since the same
+            // rename looks different across inotify/FSEvents/ReadDirectoryChangesW.
+            for id in ids {
+                let current = list_jobs(&self.qdir)?
+                    .into_iter()
+                    .find(|(x, _)| *x == id)
+                    .map(|(_, status)| status);
+                let prev = self.known.get(&id).copied();
+                match current {
+                    Some(status) if Some(status) != prev => {
+                        self.known.insert(id, status);
+                        return Ok(QueueEvent::StatusChanged { id, new: status });
+                    }
+                    None if prev.is_some() => {
+                        self.known.remove(&id);
+                        return Ok(QueueEvent::Removed { id });
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// The path of a job's log file, or one of its rotated generations (`gen ==
+/// 0` is the live log, `gen == 1` the most recently rotated-out one, etc).
+fn log_path(qdir: &Path, id: usize, gen: usize) -> PathBuf {
+    if gen == 0 {
+        qdir.join(format!("patiently.{id}.log"))
+    } else {
+        qdir.join(format!("patiently.{id}.log.{gen}"))
+    }
+}
+
+/// If a job's log has grown past the rotation threshold, shuffle the rotated
+/// generations up by one (dropping the oldest) and empty out the live log.
+///
+/// The job's command has its stdout/stderr fd open on the live log's inode
+/// for the whole run, in append mode, so we can't just `rename` it away —
+/// the child would keep writing to the renamed inode forever and the fresh
+/// path at `gen 0` would stay empty. Instead we copy its bytes out to the
+/// `gen 1` slot and truncate the live file in place; since the fd is
+/// append-mode, the child's next write lands at the new (zero) end-of-file.
+fn rotate_log(qdir: &Path, id: usize) -> anyhow::Result<()> {
+    let current = log_path(qdir, id, 0);
+    let len = match current.metadata() {
+        Ok(m) => m.len(),
+        Err(_) => return Ok(()),
+    };
+    if len < log_rotate_bytes() {
+        return Ok(());
+    }
+    let keep = log_rotate_keep();
+    let _ = std::fs::remove_file(log_path(qdir, id, keep));
+    for gen in (1..keep).rev() {
+        let _ = std::fs::rename(log_path(qdir, id, gen), log_path(qdir, id, gen + 1));
+    }
+    std::fs::copy(&current, log_path(qdir, id, 1)).context("Copying rotated log")?;
+    File::options()
+        .write(true)
+        .open(&current)
+        .context("Truncating log")?
+        .set_len(0)
+        .context("Truncating log")?;
+    Ok(())
+}
+
+/// Log rotation threshold in bytes, overridable via `$PATIENTLY_LOG_ROTATE_BYTES`.
+fn log_rotate_bytes() -> u64 {
+    env_or("PATIENTLY_LOG_ROTATE_BYTES", DEFAULT_LOG_ROTATE_BYTES)
+}
+
+/// Number of rotated log generations to keep, overridable via `$PATIENTLY_LOG_ROTATE_KEEP`.
+fn log_rotate_keep() -> usize {
+    env_or("PATIENTLY_LOG_ROTATE_KEEP", DEFAULT_LOG_ROTATE_KEEP)
+}
+
+fn env_or<T: FromStr>(var: &str, default: T) -> T {
+    std::env::var(var)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(default)
+}
+
+/// The path of a job's progress file.  While the job is `Running`, its
+/// command is pointed at this path via `$PATIENTLY_PROGRESS` and may write a
+/// single `fraction\tmessage` line to it to report how far it's got.
+fn progress_path(qdir: &Path, id: usize) -> PathBuf {
+    qdir.join(format!("patiently.{id}.progress"))
+}
+
+/// Read back the most recently reported progress for a running job, if any.
+fn read_progress(qdir: &Path, id: usize) -> Option<(f64, String)> {
+    let contents = std::fs::read_to_string(progress_path(qdir, id)).ok()?;
+    let line = contents.lines().next_back()?.trim();
+    let (fraction, message) = line.split_once('\t')?;
+    let fraction: f64 = fraction.trim().parse().ok()?;
+    Some((fraction.clamp(0.0, 1.0), message.to_string()))
+}
+
+/// Render a fixed-width ASCII progress bar for a fraction in `0.0..=1.0`.
+fn progress_bar(fraction: f64) -> String {
+    const WIDTH: usize = 20;
+    let filled = (fraction * WIDTH as f64).round() as usize;
+    format!(
+        "[{}{}]",
+        "=".repeat(filled.min(WIDTH)),
+        " ".repeat(WIDTH.saturating_sub(filled))
+    )
+}
+
+fn meta_path(qdir: &Path, id: usize) -> PathBuf {
+    qdir.join(format!("patiently.{id}.meta"))
+}
+
+fn write_meta(qdir: &Path, id: usize, meta: &Meta) -> anyhow::Result<()> {
+    let json = serde_json::to_string_pretty(meta).context("Serialising meta")?;
+    std::fs::write(meta_path(qdir, id), json).context("Writing meta file")
+}
+
+fn read_meta(qdir: &Path, id: usize) -> anyhow::Result<Meta> {
+    let s = std::fs::read_to_string(meta_path(qdir, id)).context("Reading meta file")?;
+    serde_json::from_str(&s).context("Parsing meta file")
+}
+
+fn deps_path(qdir: &Path, id: usize) -> PathBuf {
+    qdir.join(format!("patiently.{id}.deps"))
+}
+
+fn write_deps(qdir: &Path, id: usize, deps: &Deps) -> anyhow::Result<()> {
+    let json = serde_json::to_string_pretty(deps).context("Serialising deps")?;
+    std::fs::write(deps_path(qdir, id), json).context("Writing deps file")
+}
+
+fn read_deps(qdir: &Path, id: usize) -> anyhow::Result<Deps> {
+    let s = std::fs::read_to_string(deps_path(qdir, id)).context("Reading deps file")?;
+    serde_json::from_str(&s).context("Parsing deps file")
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Metadata about a single job, recorded alongside its queue file and log.
+#[derive(Default, Serialize, Deserialize)]
+struct Meta {
+    cmd: String,
+    submitted: u64,
+    started: Option<u64>,
+    ended: Option<u64>,
+    exit_code: Option<i32>,
+    // Only set while the job is running; the process group id of its child.
+    pgid: Option<i32>,
+    // How many retries this job is allowed, and how many it's used so far.
+    retries: u32,
+    attempt: u32,
+}
+
+/// A job's declared `--after`/`--tag` arguments, recorded as its dependency
+/// sidecar so that later jobs can resolve tags without re-parsing argv.
+#[derive(Default, Serialize, Deserialize)]
+struct Deps {
+    after: Vec<String>,
+    tags: Vec<String>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Enum)]
 enum Status {
     Waiting,
     Running,
     Finished,
     Failed,
     Crashed,
+    Cancelled,
 }
 impl fmt::Display for Status {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -291,6 +866,7 @@ impl fmt::Display for Status {
             Status::Finished => f.write_str("finished"),
             Status::Failed => f.write_str("failed"),
             Status::Crashed => f.write_str("crashed"),
+            Status::Cancelled => f.write_str("cancelled"),
         }
     }
 }
@@ -303,6 +879,7 @@ impl FromStr for Status {
             "finished" => Ok(Status::Finished),
             "failed" => Ok(Status::Failed),
             "crashed" => Ok(Status::Crashed),
+            "cancelled" => Ok(Status::Cancelled),
             _ => bail!("{s}: Unrecognised status"),
         }
     }
@@ -311,7 +888,7 @@ impl Status {
     fn is_finished(self) -> bool {
         match self {
             Status::Waiting | Status::Running => false,
-            Status::Finished | Status::Failed | Status::Crashed => true,
+            Status::Finished | Status::Failed | Status::Crashed | Status::Cancelled => true,
         }
     }
 }